@@ -1,12 +1,22 @@
+// The `param-discovery` feature (default-on) gates everything that
+// leans on the `sysctl` crate to enumerate `security.jail.param.*` at
+// runtime (`RULES_ALL`, `get_rules_all`, `list_all`, `get_val_by_type`,
+// and the `RuleType`/`CtlType` conversions). Consumers who only ever
+// drive `set`/`attach`/`remove`/`get_rules` with an explicit key list
+// can disable it to drop the `sysctl` dependency entirely.
 extern crate libc;
+#[cfg(feature = "param-discovery")]
 extern crate sysctl;
+#[cfg(feature = "param-discovery")]
 extern crate lazy_static;
 
+#[cfg(feature = "param-discovery")]
 use lazy_static::lazy_static;
 
 use libc::iovec;
 use libc::{jail_attach, jail_get, jail_remove, jail_set};
-use sysctl::{Ctl, CtlType, CtlValue};
+#[cfg(feature = "param-discovery")]
+use sysctl::{Ctl, CtlType, CtlValue, Sysctl};
 
 use std::collections::HashMap;
 use std::convert::*;
@@ -23,8 +33,14 @@ pub use libc::JAIL_SYS_INHERIT;
 pub use libc::JAIL_SYS_DISABLE;
 pub use libc::JAIL_SYS_NEW;
 
+#[cfg(feature = "param-discovery")]
 pub const SYSCTL_PREFIX: &str = "security.jail.param";
 
+/// Size of the scratch buffer passed to the kernel as the `errmsg` parameter.
+/// 256 bytes is the conventional size used by `jail(8)` and friends.
+const ERRMSG_BUF_LEN: usize = 256;
+
+#[cfg(feature = "param-discovery")]
 lazy_static! {
     pub static ref RULES_ALL: HashMap<String, RuleType>  = {
 
@@ -106,10 +122,34 @@ impl From<ParseIntError> for ConvertError {
 #[derive(Debug)]
 pub enum LibJailError {
     IoError(IoError),
+    #[cfg(feature = "param-discovery")]
     SysctlError(sysctl::SysctlError),
     ConvertError(ConvertError),
+    #[cfg(feature = "param-discovery")]
     MismatchCtlType,
+    #[cfg(feature = "param-discovery")]
     MismatchCtlValue,
+    /// A `jail_set`/`jail_get` call failed and the kernel filled in the
+    /// `errmsg` parameter with a human-readable explanation.
+    JailError { errno: i32, message: String },
+    /// A parameter wasn't one of the handful of well-known keys and
+    /// resolving its type requires the `param-discovery` feature, which
+    /// is disabled in this build.
+    #[cfg(not(feature = "param-discovery"))]
+    ParamDiscoveryDisabled,
+}
+
+impl LibJailError {
+    /// The OS errno behind this error, if it carries one. Lets callers
+    /// like `list` recognise `ENOENT` regardless of whether the kernel
+    /// filled in `errmsg` (-> `JailError`) or left it empty (-> `IoError`).
+    fn errno(&self) -> Option<i32> {
+        match self {
+            LibJailError::IoError(error) => error.raw_os_error(),
+            LibJailError::JailError { errno, .. } => Some(*errno),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for LibJailError {
@@ -126,6 +166,7 @@ impl From<IoError> for LibJailError {
     }
 }
 
+#[cfg(feature = "param-discovery")]
 impl From<sysctl::SysctlError> for LibJailError {
     fn from(error: sysctl::SysctlError) -> Self {
         LibJailError::SysctlError(error)
@@ -175,6 +216,36 @@ impl Modifier {
     }
 }
 
+/// Flags passed to `jail_get`'s `flags` argument, controlling which
+/// jails are visible to the lookup.
+#[derive(Debug, Clone, Copy)]
+pub struct GetFlags(i32);
+
+impl GetFlags {
+    pub fn none() -> Self {
+        GetFlags(0)
+    }
+
+    /// Also match jails that are mid-teardown (`JAIL_DYING`).
+    pub fn dying() -> Self {
+        GetFlags(libc::JAIL_DYING)
+    }
+}
+
+impl Default for GetFlags {
+    fn default() -> Self {
+        GetFlags::none()
+    }
+}
+
+impl ops::Add<GetFlags> for GetFlags {
+    type Output = GetFlags;
+
+    fn add(self, other: GetFlags) -> GetFlags {
+        GetFlags(self.0 | other.0)
+    }
+}
+
 #[derive(Debug)]
 pub enum Index {
     Jid(i32),
@@ -431,6 +502,7 @@ impl Val {
     }
 }
 
+#[cfg(feature = "param-discovery")]
 #[derive(Debug)]
 pub enum RuleType {
     Int,
@@ -441,6 +513,7 @@ pub enum RuleType {
     Unknown,
 }
 
+#[cfg(feature = "param-discovery")]
 impl From<CtlType> for RuleType {
     fn from(value: CtlType) -> RuleType {
         match value {
@@ -452,29 +525,198 @@ impl From<CtlType> for RuleType {
     }
 }
 
-pub fn set(rules: HashMap<Val, Val>, action: Action) -> Result<i32, LibJailError> {
-    let mut iovec_vec = Vec::new();
+/// Builds the `errmsg` iovec pair (key + zeroed output buffer) that gets
+/// appended to a `jail_set`/`jail_get` parameter list so the kernel can
+/// write back a human-readable failure reason.
+fn errmsg_iov(key: &CString, buffer: &mut Vec<u8>) -> [iovec; 2] {
+    [
+        iovec {
+            iov_base: key.as_ptr() as *mut _,
+            iov_len: key.as_bytes_with_nul().len(),
+        },
+        iovec {
+            iov_base: buffer.as_mut_ptr() as *mut _,
+            iov_len: buffer.len(),
+        },
+    ]
+}
 
-    for (key, value) in rules.iter() {
-        iovec_vec.push(key.to_iov());
-        iovec_vec.push(value.to_iov());
-    }
+/// Turns a (possibly empty) `errmsg` output buffer into a `LibJailError`,
+/// falling back to the bare OS error when the kernel left it empty.
+fn errmsg_error(buffer: &[u8]) -> LibJailError {
+    let io_error = IoError::last_os_error();
 
-    let jid = unsafe {
-        jail_set(
-            iovec_vec.as_slice().as_ptr() as *mut _,
-            iovec_vec.len() as u32,
-            action.0,
-        )
-    };
+    let message = unsafe { CStr::from_ptr(buffer.as_ptr() as *const _) }
+        .to_string_lossy()
+        .into_owned();
 
-    if jid > 0 {
-        Ok(jid)
+    if message.is_empty() {
+        LibJailError::IoError(io_error)
     } else {
-        Err(IoError::last_os_error())?
+        LibJailError::JailError {
+            errno: io_error.raw_os_error().unwrap_or(0),
+            message,
+        }
     }
 }
 
+/// A safe, owning builder for `jail_set`/`jail_get` parameter lists.
+///
+/// `set` and `get_rules` used to build a `Vec<iovec>` by borrowing
+/// straight into a caller-provided `HashMap<Val, Val>`, which left it up
+/// to the caller to keep every `Val` alive across the syscall. `JailParams`
+/// owns its keys and values instead, so the iovecs handed to the kernel
+/// are always backed by storage that outlives the call.
+#[derive(Debug, Default)]
+pub struct JailParams {
+    entries: Vec<(Val, Val)>,
+}
+
+impl JailParams {
+    pub fn new() -> Self {
+        JailParams { entries: Vec::new() }
+    }
+
+    /// Inserts a key/value pair, overwriting any existing entry for the
+    /// same key (mirroring the `HashMap<Val, Val>` semantics this builder
+    /// replaced) so a parameter never ends up listed twice in the iovec
+    /// list handed to the kernel.
+    fn push(&mut self, key: Val, value: Val) -> &mut Self {
+        if let Some(entry) = self.entries.iter_mut().find(|(k, _)| *k == key) {
+            entry.1 = value;
+        } else {
+            self.entries.push((key, value));
+        }
+        self
+    }
+
+    /// Inserts an already-converted key/value pair. Used internally by
+    /// `set`/`get_rules` when building a `JailParams` out of a
+    /// `HashMap<Val, Val>`.
+    pub(crate) fn insert_val(&mut self, key: Val, value: Val) -> &mut Self {
+        self.push(key, value)
+    }
+
+    pub fn set_str(&mut self, key: &str, value: &str) -> Result<&mut Self, ConvertError> {
+        Ok(self.push(key.try_into()?, value.try_into()?))
+    }
+
+    pub fn set_i32(&mut self, key: &str, value: i32) -> Result<&mut Self, ConvertError> {
+        Ok(self.push(key.try_into()?, value.try_into()?))
+    }
+
+    pub fn set_u32(&mut self, key: &str, value: u32) -> Result<&mut Self, ConvertError> {
+        Ok(self.push(key.try_into()?, value.try_into()?))
+    }
+
+    pub fn set_u64(&mut self, key: &str, value: u64) -> Result<&mut Self, ConvertError> {
+        Ok(self.push(key.try_into()?, value.try_into()?))
+    }
+
+    pub fn set_bool(&mut self, key: &str, value: bool) -> Result<&mut Self, ConvertError> {
+        Ok(self.push(key.try_into()?, value.try_into()?))
+    }
+
+    pub fn set_ip4(&mut self, key: &str, value: Ipv4Addr) -> Result<&mut Self, ConvertError> {
+        Ok(self.push(key.try_into()?, value.try_into()?))
+    }
+
+    pub fn set_ip6(&mut self, key: &str, value: Ipv6Addr) -> Result<&mut Self, ConvertError> {
+        Ok(self.push(key.try_into()?, value.try_into()?))
+    }
+
+    /// Materialises the parameter list as iovecs borrowing straight into
+    /// `self`'s owned `CString`s and buffers. Kept crate-private: `set`
+    /// and `get` consume the result within the same call, before `self`
+    /// can go away, which is the only sound use of it.
+    fn iovec(&self) -> Vec<iovec> {
+        let mut iovec_vec = Vec::with_capacity(self.entries.len() * 2);
+
+        for (key, value) in self.entries.iter() {
+            iovec_vec.push(key.to_iov());
+            iovec_vec.push(value.to_iov());
+        }
+
+        iovec_vec
+    }
+
+    /// Calls `jail_set` with this parameter list.
+    pub fn set(&self, action: Action) -> Result<i32, LibJailError> {
+        let mut iovec_vec = self.iovec();
+
+        let errmsg_key = CString::new("errmsg").unwrap();
+        let mut errmsg_buf = vec![0u8; ERRMSG_BUF_LEN];
+        iovec_vec.extend_from_slice(&errmsg_iov(&errmsg_key, &mut errmsg_buf));
+
+        let jid = unsafe {
+            jail_set(
+                iovec_vec.as_slice().as_ptr() as *mut _,
+                iovec_vec.len() as u32,
+                action.0,
+            )
+        };
+
+        if jid > 0 {
+            Ok(jid)
+        } else {
+            Err(errmsg_error(&errmsg_buf))
+        }
+    }
+
+    /// Calls `jail_get` with this parameter list, returning the syscall's
+    /// raw return value (the matched jid) alongside the requested
+    /// parameters. Shared by `get` and `list`, which need the raw jid to
+    /// drive the `lastjid` enumeration protocol.
+    fn call_get(&self, flags: GetFlags) -> Result<(i32, HashMap<String, OutVal>), LibJailError> {
+        let mut iovec_vec = self.iovec();
+
+        let errmsg_key = CString::new("errmsg").unwrap();
+        let mut errmsg_buf = vec![0u8; ERRMSG_BUF_LEN];
+        iovec_vec.extend_from_slice(&errmsg_iov(&errmsg_key, &mut errmsg_buf));
+
+        let result = unsafe {
+            jail_get(
+                iovec_vec.as_slice().as_ptr() as *mut _,
+                iovec_vec.len() as u32,
+                flags.0,
+            )
+        };
+
+        if result >= 0 {
+
+            let mut out_hash_map: HashMap<String, OutVal> = HashMap::new();
+
+            for (key, value) in self.entries.iter() {
+                out_hash_map.insert(
+                    key.clone().into_string()?,
+                    value.clone().into()
+                    );
+            }
+
+            Ok((result, out_hash_map))
+
+        } else {
+            Err(errmsg_error(&errmsg_buf))
+        }
+    }
+
+    /// Calls `jail_get` with this parameter list, returning the requested
+    /// parameters as a `String`-keyed map.
+    pub fn get(&self, flags: GetFlags) -> Result<HashMap<String, OutVal>, LibJailError> {
+        self.call_get(flags).map(|(_jid, out_hash_map)| out_hash_map)
+    }
+}
+
+pub fn set(rules: HashMap<Val, Val>, action: Action) -> Result<i32, LibJailError> {
+    let mut params = JailParams::new();
+
+    for (key, value) in rules {
+        params.insert_val(key, value);
+    }
+
+    params.set(action)
+}
+
 pub fn attach(jid: i32) -> Result<(), LibJailError> {
 
     let result = unsafe { jail_attach(jid) };
@@ -520,6 +762,17 @@ fn get_val_by_key(key: &str) -> Option<Val> {
 
 }
 
+/// `param-discovery` is disabled, so there's no sysctl to ask; any key
+/// `get_val_by_key` doesn't already know is unsupported in this build.
+#[cfg(not(feature = "param-discovery"))]
+fn get_val_by_type(_key: &str) -> Result<Val, LibJailError> {
+    Err(LibJailError::ParamDiscoveryDisabled)
+}
+
+/// Falls back to sysctl when a key isn't one of the well-known params
+/// `get_val_by_key` handles, asking `security.jail.param.<key>` for the
+/// parameter's type so the right zeroed output buffer can be allocated.
+#[cfg(feature = "param-discovery")]
 fn get_val_by_type(key: &str) -> Result<Val, LibJailError> {
 
     let rule = format!("{}.{}", SYSCTL_PREFIX, key);
@@ -564,12 +817,17 @@ fn get_val_by_type(key: &str) -> Result<Val, LibJailError> {
     }
 }
 
-pub fn get_rules<R>(index: impl Into<Index>, keys: R) -> Result<HashMap<String, OutVal>, LibJailError>
+/// Builds the `Val -> Val` request map shared by `get_rules` and `list`:
+/// each requested key is resolved to a zeroed output buffer of the right
+/// shape, either from the small set of well-known keys in
+/// `get_val_by_key` or, failing that, by asking sysctl about the
+/// parameter's type. Keys whose sysctl is not readable are left out of
+/// the map; without `param-discovery`, any other key is an error.
+fn build_value_map<R>(keys: R) -> Result<HashMap<Val, Val>, LibJailError>
 where
     R: IntoIterator,
     R::Item: Into<String>,
 {
-    let mut iovec_vec = Vec::new();
     let mut hash_map: HashMap<Val, Val> = HashMap::new();
 
     for key in keys {
@@ -592,6 +850,7 @@ where
             Ok(value) => {
                 hash_map.insert(key, value);
             },
+            #[cfg(feature = "param-discovery")]
             Err(LibJailError::SysctlError(sysctl::SysctlError::NoReadAccess)) => {
 
                 continue;
@@ -603,54 +862,101 @@ where
 
     }
 
+    Ok(hash_map)
+}
+
+/// Without the `param-discovery` feature, only the well-known keys
+/// `get_val_by_key` handles (`ip4.addr`, `ip6.addr`, `ip4`, `ip6`) can be
+/// requested; any other key returns `LibJailError::ParamDiscoveryDisabled`
+/// since there's no sysctl to ask for its type.
+pub fn get_rules<R>(index: impl Into<Index>, keys: R, flags: GetFlags) -> Result<HashMap<String, OutVal>, LibJailError>
+where
+    R: IntoIterator,
+    R::Item: Into<String>,
+{
+    let mut params = JailParams::new();
+
+    for (key, value) in build_value_map(keys)? {
+        params.insert_val(key, value);
+    }
+
     match index.into() {
         Index::Jid(jid) => {
-            hash_map.insert("jid".try_into()?, jid.try_into()?);
+            params.insert_val("jid".try_into()?, jid.try_into()?);
         },
         Index::Name(name) => {
-            hash_map.insert("name".try_into()?, name.try_into()?);
+            params.insert_val("name".try_into()?, name.try_into()?);
         },
     }
 
-    for (key, value) in hash_map.iter() {
-        iovec_vec.push(key.to_iov());
-        iovec_vec.push(value.to_iov());
-    }
+    params.get(flags)
+}
 
-    let result = unsafe {
-        jail_get(
-            iovec_vec.as_slice().as_ptr() as *mut _,
-            iovec_vec.len() as u32,
-            0,
-        )
-    };
+#[cfg(feature = "param-discovery")]
+pub fn get_rules_all(index: impl Into<Index>, flags: GetFlags) -> Result<HashMap<String, OutVal>, LibJailError> {
+
+    let names: Vec<String> = RULES_ALL.keys()
+        .map(|key| key.clone())
+        .collect();
 
-    if result >= 0 {
+    get_rules(index, names, flags)
 
-        let mut out_hash_map: HashMap<String, OutVal> = HashMap::new();
+}
 
-        for (key, value) in hash_map.iter_mut() {
+/// Enumerates every jail on the host, returning one `HashMap` of the
+/// requested `keys` per jail (plus the jail's `jid`).
+///
+/// This drives the `lastjid` iteration protocol documented for
+/// `jail_get(2)`: starting from `lastjid = 0`, each call returns the jid
+/// of the next jail whose jid is greater than `lastjid`, until the
+/// kernel reports `ENOENT`.
+///
+/// Without the `param-discovery` feature, only the well-known keys
+/// `get_val_by_key` handles (`ip4.addr`, `ip6.addr`, `ip4`, `ip6`) can be
+/// requested; see `get_rules` for details.
+pub fn list<R>(keys: R, flags: GetFlags) -> Result<Vec<HashMap<String, OutVal>>, LibJailError>
+where
+    R: IntoIterator + Clone,
+    R::Item: Into<String>,
+{
+    let mut jails = Vec::new();
+    let mut lastjid: i32 = 0;
 
-            out_hash_map.insert(
-                key.clone().into_string()?,
-                value.clone().into()
-                );
+    loop {
 
+        let mut params = JailParams::new();
+
+        for (key, value) in build_value_map(keys.clone())? {
+            params.insert_val(key, value);
         }
+        params.insert_val("lastjid".try_into()?, lastjid.try_into()?);
 
-        Ok(out_hash_map)
+        let (jid, mut out_hash_map) = match params.call_get(flags) {
+            Ok(result) => result,
+            Err(ref error) if error.errno() == Some(libc::ENOENT) => break,
+            Err(error) => return Err(error),
+        };
+
+        out_hash_map.remove("lastjid");
+        out_hash_map.insert("jid".to_string(), OutVal::I32(jid));
+
+        jails.push(out_hash_map);
+        lastjid = jid;
 
-    } else {
-        Err(IoError::last_os_error())?
     }
+
+    Ok(jails)
 }
 
-pub fn get_rules_all(index: impl Into<Index>) -> Result<HashMap<String, OutVal>, LibJailError> {
+/// Convenience wrapper mirroring `get_rules_all`: enumerates every jail
+/// with every parameter known to sysctl.
+#[cfg(feature = "param-discovery")]
+pub fn list_all(flags: GetFlags) -> Result<Vec<HashMap<String, OutVal>>, LibJailError> {
 
     let names: Vec<String> = RULES_ALL.keys()
         .map(|key| key.clone())
         .collect();
 
-    get_rules(index, names)
+    list(names, flags)
 
 }